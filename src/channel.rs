@@ -2,11 +2,17 @@
 //! Raw communication channel to the FUSE kernel driver.
 //!
 
-use std::{os, str};
+use std::io;
 use std::ffi::{CString, CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{PathBuf, Path};
-use libc::{c_int, c_void, size_t};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use libc::{c_char, c_int, c_void, size_t};
 use fuse::{fuse_args, fuse_mount_compat25, fuse_unmount_compat22};
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
 
 // Libc provides iovec based I/O using readv and writev functions
 #[allow(dead_code, non_camel_case_types)]
@@ -35,13 +41,20 @@ mod libc {
     pub const PATH_MAX: usize = 4096;
 }
 
-/// Wrapper around libc's realpath.  Returns the errno value if the real path cannot be obtained.
+/// Builds a `CString` from an arbitrary-byte-sequence path or option,
+/// without requiring it to be valid UTF-8. Fails only if the bytes contain
+/// an interior NUL, which can't be represented in a C string.
+fn cstring_from_os_str (s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Wrapper around libc's realpath.  Returns an io error if the real path cannot be obtained.
 /// FIXME: Use Rust's realpath method once available in std (see also https://github.com/mozilla/rust/issues/11857)
-fn real_path (path: &CStr) -> Result<CString, i32> {
+fn real_path (path: &CStr) -> io::Result<CString> {
     let mut resolved = [0; libc::PATH_MAX];
     unsafe {
         if libc::realpath(path.as_ptr(), resolved.as_mut_ptr()).is_null() {
-            Err(os::errno())
+            Err(io::Error::last_os_error())
         } else {
             // FIXME: Build CString from &[c_char] in a more elegant way
             let cresolved = CStr::from_ptr(resolved.as_ptr());
@@ -52,18 +65,148 @@ fn real_path (path: &CStr) -> Result<CString, i32> {
 
 /// Helper function to provide options as a fuse_args struct
 /// (which contains an argc count and an argv pointer)
-fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T> (options: &[&OsStr], f: F) -> T {
+fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T> (options: &[&OsStr], f: F) -> io::Result<T> {
     let mut args: Vec<CString> = vec![CString::new("rust-fuse").unwrap()];
-    // FIXME: Convert &OsStr to CString without utf-8 restrictions and without copying
-    args.extend(options.iter().map(|s| CString::new(s.to_str().unwrap()).unwrap() ));
+    for opt in options {
+        args.push(try!(cstring_from_os_str(opt)));
+    }
     let argptrs: Vec<*const i8> = args.iter().map(|s| s.as_ptr()).collect();
-    f(&fuse_args { argc: argptrs.len() as i32, argv: argptrs.as_ptr(), allocated: 0 })
+    Ok(f(&fuse_args { argc: argptrs.len() as i32, argv: argptrs.as_ptr(), allocated: 0 }))
+}
+
+/// Highest FUSE kernel protocol minor version this build can negotiate,
+/// selected by the highest `abi-7-9`..`abi-7-19` Cargo feature enabled (each
+/// feature enables the ones below it, so only the top one need be checked).
+/// Defaults to the `fuse_mount_compat25` baseline when none are enabled.
+#[cfg(feature = "abi-7-19")]
+const MAX_MINOR_VERSION: u32 = 19;
+#[cfg(all(not(feature = "abi-7-19"), feature = "abi-7-18"))]
+const MAX_MINOR_VERSION: u32 = 18;
+#[cfg(all(not(feature = "abi-7-18"), feature = "abi-7-17"))]
+const MAX_MINOR_VERSION: u32 = 17;
+#[cfg(all(not(feature = "abi-7-17"), feature = "abi-7-16"))]
+const MAX_MINOR_VERSION: u32 = 16;
+#[cfg(all(not(feature = "abi-7-16"), feature = "abi-7-15"))]
+const MAX_MINOR_VERSION: u32 = 15;
+#[cfg(all(not(feature = "abi-7-15"), feature = "abi-7-14"))]
+const MAX_MINOR_VERSION: u32 = 14;
+#[cfg(all(not(feature = "abi-7-14"), feature = "abi-7-13"))]
+const MAX_MINOR_VERSION: u32 = 13;
+#[cfg(all(not(feature = "abi-7-13"), feature = "abi-7-12"))]
+const MAX_MINOR_VERSION: u32 = 12;
+#[cfg(all(not(feature = "abi-7-12"), feature = "abi-7-11"))]
+const MAX_MINOR_VERSION: u32 = 11;
+#[cfg(all(not(feature = "abi-7-11"), feature = "abi-7-10"))]
+const MAX_MINOR_VERSION: u32 = 10;
+#[cfg(all(not(feature = "abi-7-10"), feature = "abi-7-9"))]
+const MAX_MINOR_VERSION: u32 = 9;
+#[cfg(not(feature = "abi-7-9"))]
+const MAX_MINOR_VERSION: u32 = 8;
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_INIT: u32 = 26;
+
+/// Mirrors the kernel's `fuse_in_header`, which prefixes every request.
+#[repr(C)]
+struct fuse_in_header {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    padding: u32,
+}
+
+/// Mirrors the kernel's `fuse_init_in`, the body of the first request the
+/// kernel ever sends on a freshly mounted channel.
+#[repr(C)]
+struct fuse_init_in {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+/// Mirrors the kernel's `fuse_out_header`, which prefixes every reply.
+#[repr(C)]
+struct fuse_out_header {
+    len: u32,
+    error: i32,
+    unique: u64,
+}
+
+/// Mirrors the kernel's `fuse_init_out`, our reply body to `FUSE_INIT`.
+#[repr(C)]
+struct fuse_init_out {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+}
+
+/// Performs the `FUSE_INIT` handshake that the kernel always starts a fresh
+/// mount with: blocks for the INIT request, replies with the minor version
+/// this build supports (`MAX_MINOR_VERSION`, itself capped by the enabled
+/// `abi-7-*` features), and returns the negotiated `(major, minor)` so it
+/// can be recorded on the `Channel`.
+fn negotiate_init (fd: c_int) -> io::Result<(u32, u32)> {
+    let in_header_len = std::mem::size_of::<fuse_in_header>();
+    let mut buffer = vec![0u8; in_header_len + std::mem::size_of::<fuse_init_in>()];
+    loop {
+        let rc = unsafe { ::libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if (rc as usize) < in_header_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short FUSE_INIT request"));
+        }
+        let in_header = unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const fuse_in_header) };
+        if in_header.opcode != FUSE_INIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected FUSE_INIT as the first request"));
+        }
+        let init_in = unsafe { std::ptr::read_unaligned(buffer.as_ptr().add(in_header_len) as *const fuse_init_in) };
+        let minor = init_in.minor.min(MAX_MINOR_VERSION);
+
+        let init_out = fuse_init_out {
+            major: FUSE_KERNEL_VERSION,
+            minor: minor,
+            max_readahead: init_in.max_readahead,
+            flags: 0,
+            max_background: 0,
+            congestion_threshold: 0,
+            max_write: 4096,
+        };
+        let out_header = fuse_out_header {
+            len: (std::mem::size_of::<fuse_out_header>() + std::mem::size_of::<fuse_init_out>()) as u32,
+            error: 0,
+            unique: in_header.unique,
+        };
+        let iovecs = [
+            libc::iovec { iov_base: &out_header as *const _ as *const c_void, iov_len: std::mem::size_of::<fuse_out_header>() as size_t },
+            libc::iovec { iov_base: &init_out as *const _ as *const c_void, iov_len: std::mem::size_of::<fuse_init_out>() as size_t },
+        ];
+        let wc = unsafe { libc::writev(fd, iovecs.as_ptr(), iovecs.len() as c_int) };
+        if wc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok((init_in.major, minor));
+    }
 }
 
 /// A raw communication channel to the FUSE kernel driver
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
+    protocol_version: Mutex<Option<(u32, u32)>>,
 }
 
 impl Channel {
@@ -71,19 +214,66 @@ impl Channel {
     /// given path. The kernel driver will delegate filesystem operations of
     /// the given path to the channel. If the channel is dropped, the path is
     /// unmounted.
-    pub fn new (mountpoint: &Path, options: &[&OsStr]) -> Result<Channel, i32> {
-        // FIXME: Convert &Path to CStr without utf-8 restrictions and without copying
-        let mnt = CString::new(mountpoint.to_str().unwrap()).unwrap();
+    pub fn new (mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
+        Channel::mount(mountpoint, options, MountBackend::Libfuse, false)
+    }
+
+    /// Create a new communication channel like `new`, but put the kernel fd
+    /// into non-blocking mode (`O_NONBLOCK`) first. Use `as_raw_fd` to
+    /// register the channel with an external reactor (e.g. mio/epoll) and
+    /// call `receive` only once the fd is reported readable; it returns an
+    /// error of kind `io::ErrorKind::WouldBlock` instead of blocking when no
+    /// request is pending yet. This allows driving many mounts from a single
+    /// thread.
+    pub fn new_nonblocking (mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
+        Channel::mount(mountpoint, options, MountBackend::Libfuse, true)
+    }
+
+    /// Create a new communication channel like `new`, but obtain the kernel
+    /// fd via the given `MountBackend` instead of always delegating to
+    /// libfuse.
+    pub fn new_with_backend (mountpoint: &Path, options: &[&OsStr], backend: MountBackend) -> io::Result<Channel> {
+        Channel::mount(mountpoint, options, backend, false)
+    }
+
+    fn mount (mountpoint: &Path, options: &[&OsStr], backend: MountBackend, nonblocking: bool) -> io::Result<Channel> {
+        let mnt = try!(cstring_from_os_str(mountpoint.as_os_str()));
         real_path(&mnt).and_then(|mnt| {
-            with_fuse_args(options, |args| {
-                let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
-                if fd < 0 {
-                    Err(os::errno())
-                } else {
-                    // FIXME: Convert CString to PathBuf without utf-8 restrictions and without copying
-                    let mountpoint = PathBuf::new(str::from_utf8(mnt.as_bytes()).unwrap());
-                    Ok(Channel { mountpoint: mountpoint, fd: fd })
+            let fd = match backend {
+                MountBackend::Libfuse => with_fuse_args(options, |args| {
+                    let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
+                    if fd < 0 { Err(io::Error::last_os_error()) } else { Ok(fd) }
+                }).and_then(|r| r),
+                MountBackend::Direct => mount_direct(&mnt, options),
+            };
+            fd.and_then(|fd| {
+                // Libfuse already performs the FUSE_INIT handshake itself
+                // (and hands the negotiated version to its own dispatch
+                // loop), so doing it again here would steal that request
+                // out from under it. Only the Direct backend needs us to
+                // negotiate it ourselves.
+                let version = match backend {
+                    MountBackend::Direct => match negotiate_init(fd) {
+                        Ok(version) => Some(version),
+                        Err(e) => {
+                            unsafe { ::libc::close(fd); }
+                            return Err(e);
+                        },
+                    },
+                    MountBackend::Libfuse => None,
+                };
+                if nonblocking {
+                    if let Err(e) = set_nonblocking(fd) {
+                        unsafe { ::libc::close(fd); }
+                        return Err(e);
+                    }
                 }
+                let mountpoint = PathBuf::from(OsStr::from_bytes(mnt.as_bytes()));
+                let channel = Channel { mountpoint: mountpoint, fd: fd, protocol_version: Mutex::new(None) };
+                if let Some(version) = version {
+                    channel.set_protocol_version(version.0, version.1);
+                }
+                Ok(channel)
             })
         })
     }
@@ -93,11 +283,20 @@ impl Channel {
         &self.mountpoint
     }
 
-    /// Receives data up to the capacity of the given buffer (can block).
-    pub fn receive (&self, buffer: &mut Vec<u8>) -> Result<(), i32> {
+    /// Returns the raw fuse device fd backing this channel, so it can be
+    /// registered with an external reactor.
+    pub fn as_raw_fd (&self) -> c_int {
+        self.fd
+    }
+
+    /// Receives data up to the capacity of the given buffer (can block,
+    /// unless the channel was created with `new_nonblocking`, in which case
+    /// an error of kind `io::ErrorKind::WouldBlock` is returned when no
+    /// request is pending).
+    pub fn receive (&self, buffer: &mut Vec<u8>) -> io::Result<()> {
         let rc = unsafe { ::libc::read(self.fd, buffer.as_ptr() as *mut c_void, buffer.capacity() as size_t) };
         if rc < 0 {
-            Err(os::errno())
+            Err(io::Error::last_os_error())
         } else {
             unsafe { buffer.set_len(rc as usize); }
             Ok(())
@@ -114,6 +313,212 @@ impl Channel {
         // dropping the channel, it'll return an EBADF error.
         ChannelSender { fd: self.fd }
     }
+
+    /// Records the FUSE protocol version negotiated at `FUSE_INIT` time.
+    /// `mount` calls this itself right after the kernel's INIT handshake;
+    /// a dispatch layer that renegotiates later (or parses the INIT reply
+    /// itself) can call this again so that later replies are tailored to
+    /// what the kernel (and this build's enabled `abi-7-*` features)
+    /// actually support. It has no other effect on the channel.
+    pub fn set_protocol_version (&self, major: u32, minor: u32) {
+        *self.protocol_version.lock().unwrap() = Some((major, minor.min(MAX_MINOR_VERSION)));
+    }
+
+    /// Returns the `(major, minor)` FUSE protocol version negotiated at
+    /// `FUSE_INIT` time, or `None` if `set_protocol_version` hasn't been
+    /// called yet.
+    pub fn protocol_version (&self) -> Option<(u32, u32)> {
+        *self.protocol_version.lock().unwrap()
+    }
+}
+
+/// Selects how a `Channel` obtains its kernel fd.
+#[derive(Clone, Copy)]
+pub enum MountBackend {
+    /// Delegate entirely to libfuse's `fuse_mount_compat25` (the default).
+    Libfuse,
+    /// Open `/dev/fuse` directly and issue the `mount(2)` syscall ourselves,
+    /// without linking libfuse. Falls back to forking `fusermount3` (and
+    /// receiving the fd back over a `SCM_RIGHTS` unix-socket message) when
+    /// the process lacks `CAP_SYS_ADMIN`. Linux only.
+    Direct,
+}
+
+/// Implements `MountBackend::Direct`: open `/dev/fuse` and call `mount(2)`
+/// ourselves with the options the kernel driver expects
+/// (`fd=<n>,rootmode=<octal>,user_id=<uid>,group_id=<gid>`, plus any
+/// passthrough options), falling back to `mount_via_fusermount` when we
+/// lack the privilege to call `mount(2)` directly.
+fn mount_direct (mnt: &CString, options: &[&OsStr]) -> io::Result<c_int> {
+    let dev = CString::new("/dev/fuse").unwrap();
+    let fd = unsafe { ::libc::open(dev.as_ptr(), ::libc::O_RDWR | ::libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let rootmode = unsafe {
+        let mut st: ::libc::stat = std::mem::zeroed();
+        if ::libc::stat(mnt.as_ptr(), &mut st) < 0 {
+            let err = io::Error::last_os_error();
+            ::libc::close(fd);
+            return Err(err);
+        }
+        st.st_mode & ::libc::S_IFMT
+    };
+
+    let mut mount_options = format!("fd={},rootmode={:o},user_id={},group_id={}",
+                                     fd, rootmode, unsafe { ::libc::getuid() }, unsafe { ::libc::getgid() }).into_bytes();
+    for opt in options {
+        mount_options.push(b',');
+        mount_options.extend_from_slice(opt.as_bytes());
+    }
+
+    let source = CString::new("rust-fuse").unwrap();
+    let fstype = CString::new("fuse").unwrap();
+    let data = match CString::new(mount_options) {
+        Ok(data) => data,
+        Err(e) => {
+            unsafe { ::libc::close(fd); }
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        },
+    };
+    let rc = unsafe {
+        ::libc::mount(source.as_ptr(), mnt.as_ptr(), fstype.as_ptr(), 0, data.as_ptr() as *const c_void)
+    };
+    if rc < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { ::libc::close(fd); }
+        if err.raw_os_error() == Some(::libc::EPERM) {
+            return mount_via_fusermount(mnt, options);
+        }
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+/// Fallback for `MountBackend::Direct` when the process lacks
+/// `CAP_SYS_ADMIN`: fork `fusermount3 -o <options> <mountpoint>`, which
+/// performs the privileged `mount(2)` itself and hands the resulting fuse
+/// device fd back to us over a `SCM_RIGHTS` unix-socket message, the same
+/// handoff the stock `fusermount` helper and libfuse use.
+fn mount_via_fusermount (mnt: &CString, options: &[&OsStr]) -> io::Result<c_int> {
+    let mut opts = Vec::new();
+    for (i, opt) in options.iter().enumerate() {
+        if i > 0 { opts.push(b','); }
+        opts.extend_from_slice(opt.as_bytes());
+    }
+    let opts_c = match CString::new(opts) {
+        Ok(opts_c) => opts_c,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+    };
+
+    let mut sockets: [c_int; 2] = [0; 2];
+    if unsafe { ::libc::socketpair(::libc::AF_UNIX, ::libc::SOCK_STREAM, 0, sockets.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (parent_sock, child_sock) = (sockets[0], sockets[1]);
+
+    // fork(2) only leaves the calling thread running in the child, so until
+    // the exec the child may only call async-signal-safe functions; malloc
+    // (which CString::new and setenv both use internally) is not among them
+    // and can deadlock if another thread held the allocator lock at the
+    // moment of the fork. So every CString and the child's environment
+    // block are built up front, here in the parent, and the child does
+    // nothing but close, execve and _exit.
+    // execve doesn't search PATH, so look fusermount3 up here.
+    let prog_path = std::env::var_os("PATH")
+        .and_then(|paths| std::env::split_paths(&paths).map(|dir| dir.join("fusermount3")).find(|p| p.is_file()))
+        .unwrap_or_else(|| PathBuf::from("fusermount3"));
+    let prog = CString::new(prog_path.as_os_str().as_bytes()).unwrap();
+    let opt_flag = CString::new("-o").unwrap();
+    let commfd_entry = CString::new(format!("_FUSE_COMMFD={}", child_sock)).unwrap();
+    let argv = [prog.as_ptr(), opt_flag.as_ptr(), opts_c.as_ptr(), mnt.as_ptr(), std::ptr::null()];
+    let mut envp: Vec<*const c_char> = Vec::new();
+    unsafe {
+        let mut p = ::libc::environ;
+        while !(*p).is_null() {
+            envp.push(*p);
+            p = p.add(1);
+        }
+    }
+    envp.push(commfd_entry.as_ptr());
+    envp.push(std::ptr::null());
+
+    let pid = unsafe { ::libc::fork() };
+    if pid < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { ::libc::close(parent_sock); ::libc::close(child_sock); }
+        return Err(err);
+    }
+    if pid == 0 {
+        unsafe {
+            ::libc::close(parent_sock);
+            ::libc::execve(prog.as_ptr(), argv.as_ptr(), envp.as_ptr());
+            // execve only returns on failure
+            ::libc::_exit(1);
+        }
+    }
+
+    unsafe { ::libc::close(child_sock); }
+    let result = unsafe { recv_fd(parent_sock) };
+    unsafe { ::libc::close(parent_sock); }
+    let mut status: c_int = 0;
+    unsafe { ::libc::waitpid(pid, &mut status, 0); }
+    result
+}
+
+/// Receives a single file descriptor sent as ancillary `SCM_RIGHTS` data
+/// over a unix domain socket, as `fusermount3` does when handing back the
+/// fuse device fd it opened with elevated privilege.
+unsafe fn recv_fd (sock: c_int) -> io::Result<c_int> {
+    let mut dummy = [0u8; 1];
+    let mut iov = ::libc::iovec { iov_base: dummy.as_mut_ptr() as *mut c_void, iov_len: dummy.len() as size_t };
+    let mut cmsg_buf = [0u8; 64];
+    let mut msg: ::libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    if ::libc::recvmsg(sock, &mut msg, 0) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let cmsg = ::libc::CMSG_FIRSTHDR(&msg);
+    if cmsg.is_null() || (*cmsg).cmsg_type != ::libc::SCM_RIGHTS {
+        return Err(io::Error::from_raw_os_error(::libc::EIO));
+    }
+    Ok(*(::libc::CMSG_DATA(cmsg) as *const c_int))
+}
+
+/// Sets the `O_NONBLOCK` flag on a fuse device fd, so that `read` returns
+/// an `io::ErrorKind::WouldBlock` error instead of blocking when no request
+/// is pending.
+fn set_nonblocking (fd: c_int) -> io::Result<()> {
+    unsafe {
+        let flags = ::libc::fcntl(fd, ::libc::F_GETFL, 0);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if ::libc::fcntl(fd, ::libc::F_SETFL, flags | ::libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Source for Channel {
+    fn register (&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        SourceFd(&self.fd).register(registry, token, interests)
+    }
+
+    fn reregister (&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        SourceFd(&self.fd).reregister(registry, token, interests)
+    }
+
+    fn deregister (&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.fd).deregister(registry)
+    }
 }
 
 impl Drop for Channel {
@@ -122,44 +527,176 @@ impl Drop for Channel {
         // Close the communication channel to the kernel driver
         // (closing it before unnmount prevents sync unmount deadlock)
         unsafe { ::libc::close(self.fd); }
-        // Unmount this channel's mount point
-        unmount(&self.mountpoint);
+        // Unmount this channel's mount point. Nothing can be done with the
+        // result here (Drop can't return one), so just log a failure.
+        if let Err(e) = unmount(&self.mountpoint) {
+            eprintln!("rust-fuse: failed to unmount {}: {}", self.mountpoint.display(), e);
+        }
     }
 }
 
-#[derive(Copy)]
+#[derive(Copy, Clone)]
 pub struct ChannelSender {
     fd: c_int,
 }
 
 impl ChannelSender {
     /// Send all data in the slice of slice of bytes in a single write (can block).
-    pub fn send (&self, buffer: &[&[u8]]) -> Result<(), i32> {
+    pub fn send (&self, buffer: &[&[u8]]) -> io::Result<()> {
         let iovecs: Vec<libc::iovec> = buffer.iter().map(|d| {
             libc::iovec { iov_base: d.as_ptr() as *const c_void, iov_len: d.len() as size_t }
         }).collect();
         let rc = unsafe { libc::writev(self.fd, iovecs.as_ptr(), iovecs.len() as c_int) };
         if rc < 0 {
-            Err(os::errno())
+            Err(io::Error::last_os_error())
         } else {
             Ok(())
         }
     }
 }
 
+/// An async wrapper around a non-blocking `Channel`, for embedding rust-fuse
+/// in futures-based runtimes (Tokio, async-std) without dedicating a
+/// blocking thread to the session loop. Construct from a channel created via
+/// `Channel::new_nonblocking` and register it with an async reactor using
+/// its `mio::event::Source` impl; call `wake` whenever that reactor reports
+/// the fd readable.
+pub struct AsyncChannel {
+    channel: Channel,
+    read_waker: Mutex<Option<Waker>>,
+    write_waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl AsyncChannel {
+    /// Wraps an existing non-blocking channel for use with `poll_receive`.
+    pub fn new (channel: Channel) -> AsyncChannel {
+        AsyncChannel { channel: channel, read_waker: Mutex::new(None), write_waker: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint (&self) -> &Path {
+        self.channel.mountpoint()
+    }
+
+    /// Polls for a request, filling `buffer` when one is ready. Parks the
+    /// task's waker and returns `Poll::Pending` when the fd isn't readable
+    /// yet (i.e. the underlying `receive` yielded an `io::ErrorKind::WouldBlock`
+    /// error); the reactor driving this channel's `Source` registration is
+    /// responsible for calling `wake` once the fd becomes readable again.
+    pub fn poll_receive (&self, cx: &mut Context, buffer: &mut Vec<u8>) -> Poll<io::Result<()>> {
+        match self.channel.receive(buffer) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                *self.read_waker.lock().unwrap() = Some(cx.waker().clone());
+                Poll::Pending
+            },
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Wakes a task parked in `poll_receive`, if any. Call this once the
+    /// reactor reports the channel's fd as readable.
+    pub fn wake (&self) {
+        if let Some(waker) = self.read_waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Wakes a task parked in `AsyncChannelSender::poll_send`, if any. Call
+    /// this once the reactor reports the channel's fd as writable.
+    pub fn wake_writable (&self) {
+        if let Some(waker) = self.write_waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Returns a sender object for this channel. See `Channel::sender`.
+    /// Shares this channel's write-readiness waker, so `wake_writable` wakes
+    /// any task parked in the returned sender's `poll_send`.
+    pub fn sender (&self) -> AsyncChannelSender {
+        AsyncChannelSender { sender: self.channel.sender(), waker: self.write_waker.clone() }
+    }
+}
+
+impl Source for AsyncChannel {
+    fn register (&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.channel.register(registry, token, interests)
+    }
+
+    fn reregister (&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.channel.reregister(registry, token, interests)
+    }
+
+    fn deregister (&mut self, registry: &Registry) -> io::Result<()> {
+        self.channel.deregister(registry)
+    }
+}
+
+#[derive(Clone)]
+pub struct AsyncChannelSender {
+    sender: ChannelSender,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl AsyncChannelSender {
+    /// Polls a send of `buffer` in a single `writev`. Parks the task's
+    /// waker and returns `Poll::Pending` while the write would block
+    /// (`io::ErrorKind::WouldBlock`); the reactor behind the `AsyncChannel`
+    /// this sender came from is responsible for calling `wake_writable`
+    /// once the fd becomes writable again.
+    pub fn poll_send (&self, cx: &mut Context, buffer: &[&[u8]]) -> Poll<io::Result<()>> {
+        match self.sender.send(buffer) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                *self.waker.lock().unwrap() = Some(cx.waker().clone());
+                Poll::Pending
+            },
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Returns the `st_dev` of `path`, i.e. the device id of the filesystem it's on.
+fn device_id (path: &CStr) -> io::Result<::libc::dev_t> {
+    unsafe {
+        let mut st: ::libc::stat = std::mem::zeroed();
+        if ::libc::stat(path.as_ptr(), &mut st) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(st.st_dev)
+    }
+}
+
+/// Checks whether `mountpoint` is still a distinct filesystem from its parent
+/// directory, i.e. whether something is still mounted there.
+fn is_still_mounted (mountpoint: &Path) -> io::Result<bool> {
+    let parent = mountpoint.parent().unwrap_or_else(|| Path::new("/"));
+    let mnt = try!(cstring_from_os_str(mountpoint.as_os_str()));
+    let parent = try!(cstring_from_os_str(parent.as_os_str()));
+    Ok(try!(device_id(&mnt)) != try!(device_id(&parent)))
+}
+
 /// Unmount an arbitrary mount point
-pub fn unmount (mountpoint: &Path) {
+pub fn unmount (mountpoint: &Path) -> io::Result<()> {
     // On OS X, fuse_unmount_compat22 attempts to call realpath, which in turn calls into the filesystem.
     // If the filesystem returns an error, the unmount does not take place, with no indication of the error
     // available to the caller.  So we call unmount directly, which is what osxfuse does anyway, since
     // we already converted to the real path when we first mounted.
-    // FIXME: Convert &Path to CStr without utf-8 restrictions and without copying
-    let mnt = CString::new(mountpoint.to_str().unwrap()).unwrap();
+    let mnt = try!(cstring_from_os_str(mountpoint.as_os_str()));
     if cfg!(target_os = "macos") {
-        unsafe { libc::unmount(mnt.as_ptr(), 0); }
+        if unsafe { libc::unmount(mnt.as_ptr(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
     } else {
+        // fuse_unmount_compat22 returns (), so it gives us no way to tell
+        // whether the unmount actually happened; compare the mountpoint's
+        // device id against its parent's afterwards to find out.
         unsafe { fuse_unmount_compat22(mnt.as_ptr()); }
+        if try!(is_still_mounted(mountpoint)) {
+            return Err(io::Error::new(io::ErrorKind::Other, "fuse_unmount_compat22 did not unmount the filesystem"));
+        }
     }
+    Ok(())
 }
 
 
@@ -175,6 +712,6 @@ mod test {
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(0)).to_bytes() }, b"rust-fuse");
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(1)).to_bytes() }, b"foo");
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(2)).to_bytes() }, b"bar");
-        });
+        }).unwrap();
     }
 }